@@ -1,15 +1,30 @@
+use std::cmp::{min, Ordering};
+use std::collections::BinaryHeap;
 use std::convert::TryInto;
 use uint::construct_uint;
 use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
 use near_sdk::collections::LookupMap;
 use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, ext_contract, near_bindgen, serde_json, AccountId, Balance, Gas, PanicOnDefault, Promise
+    env, ext_contract, near_bindgen, serde_json, AccountId, Balance, Gas, PanicOnDefault,
+    Promise, PromiseOrValue, PromiseResult
 };
 
+mod math;
+
 const FEE_DIVISOR: u32 = 1_000;
 const NO_DEPOSIT: Balance = 0;
 const GAS_FOR_SWAP: Gas = 10_000_000_000_000;
+const GAS_FOR_RESOLVE_TRANSFER: Gas = 10_000_000_000_000;
+/// Extra resolve-callback gas budgeted per settled order, on top of
+/// `GAS_FOR_RESOLVE_TRANSFER`, to cover that order's share of the rollback
+/// work `resolve_swap` may have to do (a heap push plus two LookupMap
+/// writes).
+const GAS_FOR_RESOLVE_ORDER: Gas = 5_000_000_000_000;
+/// Fixed-point scale for spot/limit prices: a price of `token_a` denominated
+/// in `token_b`, scaled by `1e18`.
+const PRICE_SCALE: u128 = 1_000_000_000_000_000_000;
 
 
 construct_uint! {
@@ -17,94 +32,117 @@ construct_uint! {
     pub struct U256(4);
 }
 
-#[near_bindgen]
-#[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
-struct Contract {
-    token_account_id: AccountId,
-    fee: u32,
-    shares: LookupMap<AccountId, Balance>,
-    shares_total_supply: Balance,
-    near_amount: Balance,
-    lp_token_amount: Balance
+/// Selects which invariant a pool prices swaps against.
+///
+/// `ConstantProduct` is the classic `x*y=k` curve, suitable for any pair.
+/// `StableSwap` prices off the Curve-style invariant, which gives much
+/// shallower slippage for assets that are expected to trade close to 1:1
+/// (e.g. wNEAR/stNEAR).
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PoolKind {
+    ConstantProduct,
+    StableSwap { amplification: u128 },
 }
 
-#[near_bindgen]
-impl Contract {
-    #[init]
-    pub fn new(token_account_id: ValidAccountId, fee: u32) -> Self {
-        assert!(!env::state_exists(), "ERR_CONTRACT_IS_INITIALIZED");
-        assert!(fee < FEE_DIVISOR, "ERR_FEE_TOO_LARGE");
-        Self {
-            token_account_id: token_account_id.into(),
-            fee,
-            shares: LookupMap::new(b"s".to_vec()),
-            shares_total_supply: 0,
-            near_amount: 0,
-            lp_token_amount: 0
-        }
-    }
-
-    pub fn add_liquidity(&mut self, sender_id: &AccountId, token_amount: U128) -> U128 {
-        let near_amount = env::attached_deposit();
-        assert!(near_amount > 0, "ERR_EMPTY_ATTACHED_DEPOSIT");
-
-        if self.shares_total_supply > 0 {
-            let expected_token_amount = near_amount * self.lp_token_amount / self.near_amount;
-            assert!(expected_token_amount <= token_amount.into(), "ERR_NOT_ENOUGH_TOKEN");
+/// An ordered pair of token account ids identifying a pool. Wrapped NEAR is
+/// just another fungible token here, so `token_a`/`token_b` cover both
+/// token/token and wNEAR/token pairs uniformly.
+pub type PoolId = (AccountId, AccountId);
 
-            let liquidity_minted = near_amount * self.shares_total_supply / self.near_amount;
-            add_to_collection(
-                &mut self.shares, 
-                sender_id, 
-                liquidity_minted
-            );
+/// One entry in a pool's resting-order heap: just enough to order entries
+/// and find the full `LimitOrder` they refer to. `price_key` is oriented so
+/// that, within a single heap, the entry most likely to already be
+/// executable is always the maximum (see `place_limit_order`); `ordinal`
+/// breaks ties FIFO.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct HeapEntry {
+    price_key: u128,
+    ordinal: u64,
+}
 
-            self.shares_total_supply += liquidity_minted;
-            self.near_amount += near_amount;
-            self.lp_token_amount += expected_token_amount;
-            expected_token_amount.into()
-        } else {
-            self.shares_total_supply = near_amount;
-            self.near_amount = near_amount;
-            self.lp_token_amount = token_amount.into();
-            add_to_collection(&mut self.shares, sender_id, near_amount);
-            token_amount
-        }
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.price_key.cmp(&other.price_key)
+            .then_with(|| other.ordinal.cmp(&self.ordinal))
     }
+}
 
-    pub fn remove_liquidity(&mut self, shares: Balance, min_near_amount: Balance, min_token_amount: Balance) -> Promise {
-        let shares_amount = shares;
-        assert!(shares_amount > 0 && self.shares_total_supply > 0, "ERR_EMPTY_SHARES");
-
-        let near_amount = (U256::from(shares_amount) * U256::from(self.near_amount) / U256::from(self.shares_total_supply)).as_u128();
-        let token_amount = (U256::from(shares_amount) * U256::from(self.lp_token_amount) / U256::from(self.shares_total_supply)).as_u128();
-        assert!(near_amount >= min_near_amount && token_amount >= min_token_amount, "ERR_MIN_AMOUNT");
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        let account_id = env::predecessor_account_id();
-        let prev_amount = self.shares.get(&account_id).unwrap_or(0);
-        assert!(prev_amount >= shares_amount, "ERR_NOT_ENOUGH_SHARES");
+/// A resting limit order: "sell `amount_in` of `token_in` once the pool's
+/// spot price crosses `limit_price`".
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+pub struct LimitOrder {
+    ordinal: u64,
+    account_id: AccountId,
+    token_in: AccountId,
+    amount_in: Balance,
+    limit_price: u128,
+    /// Storage deposit debited from the owner's prepaid storage balance
+    /// while this order rests; credited back once it's cancelled or filled.
+    storage_deposit: Balance,
+}
 
-        if prev_amount == shares_amount {
-            self.shares.remove(&account_id);
-        } else {
-            self.shares.insert(&account_id, &(prev_amount - shares_amount));
-        }
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LimitOrderView {
+    token_a: AccountId,
+    token_b: AccountId,
+    ordinal: u64,
+    token_in: AccountId,
+    amount_in: U128,
+    limit_price: U128,
+}
 
-        self.shares_total_supply -= shares_amount;
-        self.near_amount -= near_amount;
-        self.lp_token_amount -= token_amount;
-        Promise::new(account_id.clone()).transfer(near_amount);
+/// A resting order settled by `settle_limit_orders` as a side effect of a
+/// swap, carried across the swap's resolve callback so a failed settlement
+/// transfer can be unwound just like a failed swap leg.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FilledOrder {
+    ordinal: u64,
+    account_id: AccountId,
+    token_in: AccountId,
+    amount_in: U128,
+    limit_price: U128,
+    storage_deposit: U128,
+    is_sell_side: bool,
+    token_out: AccountId,
+    output_amount: U128,
+}
 
-        ext_fungible_token::ft_transfer(
-            account_id.try_into().unwrap(),
-            U128(token_amount),
-            None,
-            &self.token_account_id,
-            NO_DEPOSIT,
-            env::prepaid_gas() - GAS_FOR_SWAP
-        )
-    }
+/// A single `token_a`/`token_b` liquidity pool. Every pool keeps its own
+/// reserves, fee and share book, so pools for different pairs never share
+/// state.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Pool {
+    token_a: AccountId,
+    token_b: AccountId,
+    fee: u32,
+    pool_kind: PoolKind,
+    shares: LookupMap<AccountId, Balance>,
+    shares_total_supply: Balance,
+    reserve_a: Balance,
+    reserve_b: Balance,
+    /// Orders selling `token_a` for `token_b`, resting until the spot price
+    /// of `token_a` rises to at least their limit. Keyed by `u128::MAX -
+    /// limit_price` so the lowest limit (the first to become executable as
+    /// price rises) sorts as the heap's maximum.
+    sell_orders: Vec<HeapEntry>,
+    /// Orders selling `token_b` for `token_a` (i.e. buying `token_a`),
+    /// resting until the spot price of `token_a` falls to at most their
+    /// limit. Keyed directly by `limit_price`, so the highest limit (the
+    /// first to become executable as price falls) sorts as the heap's
+    /// maximum.
+    buy_orders: Vec<HeapEntry>,
+    next_order_ordinal: u64,
+}
 
+impl Pool {
     /*  Pricing between two reserves given input amount.
         a: input_amount, x: input_reserve, y: output_reserve
         (x+a) * (y-b) = k
@@ -119,11 +157,28 @@ impl Contract {
     pub fn get_input_price(&self, input_amount: Balance, input_reserve: Balance, output_reserve: Balance) -> Balance {
         assert!(input_reserve > 0 && output_reserve > 0, "ERR_EMPTY_RESERVE");
 
-        let input_amount_with_fee = U256::from(input_amount) * U256::from(FEE_DIVISOR - self.fee);
+        match self.pool_kind {
+            PoolKind::ConstantProduct => {
+                let input_amount_with_fee = U256::from(input_amount) * U256::from(FEE_DIVISOR - self.fee);
+                let new_input_reserve = U256::from(input_reserve) + U256::from(input_amount);
 
-        (input_amount_with_fee * U256::from(output_reserve)
-        / (U256::from(input_reserve + input_amount) *  U256::from(FEE_DIVISOR)))
-        .as_u128()
+                math::checked_as_u128(
+                    input_amount_with_fee * U256::from(output_reserve)
+                    / (new_input_reserve * U256::from(FEE_DIVISOR))
+                )
+            }
+            PoolKind::StableSwap { amplification } => {
+                let d = stable_swap_d(amplification, input_reserve, output_reserve);
+                let new_input_reserve = math::add(input_reserve, input_amount);
+                let new_output_reserve = math::checked_as_u128(stable_swap_y(amplification, new_input_reserve, d));
+                // stable_swap_y's Newton loop only converges to within 1 unit,
+                // so on tiny swaps new_output_reserve can land a unit above
+                // output_reserve; that's a true zero output, not an underflow.
+                let output_amount = output_reserve.saturating_sub(new_output_reserve);
+
+                math::mul_div(output_amount, (FEE_DIVISOR - self.fee) as Balance, FEE_DIVISOR as Balance)
+            }
+        }
     }
 
     /*  Pricing between two reserves to return given output amount.
@@ -137,59 +192,599 @@ impl Contract {
         b = a * x / y - a
         b * full_fee / substract_fee = x * a * full_fee / (y - a) * substract_fee
     */
-
     pub fn get_output_price(&self, output_amount: Balance, input_reserve: Balance, output_reserve: Balance) -> Balance {
         assert!(input_reserve > 0 && output_reserve > 0, "ERR_EMPTY_RESERVE");
 
-        (U256::from(input_reserve) * U256::from(output_amount) * U256::from(FEE_DIVISOR)
-        / (U256::from(output_reserve - output_amount) * U256::from(FEE_DIVISOR - self.fee)))
-        .as_u128()
+        match self.pool_kind {
+            PoolKind::ConstantProduct => {
+                let remaining_output_reserve = U256::from(output_reserve) - U256::from(output_amount);
+
+                math::checked_as_u128(
+                    U256::from(input_reserve) * U256::from(output_amount) * U256::from(FEE_DIVISOR)
+                    / (remaining_output_reserve * U256::from(FEE_DIVISOR - self.fee))
+                )
+            }
+            PoolKind::StableSwap { amplification } => {
+                let gross_output_amount = math::mul_div(output_amount, FEE_DIVISOR as Balance, (FEE_DIVISOR - self.fee) as Balance);
+                assert!(gross_output_amount < output_reserve, "ERR_NOT_ENOUGH_RESERVE");
+                let d = stable_swap_d(amplification, input_reserve, output_reserve);
+                let new_output_reserve = output_reserve - gross_output_amount;
+                let new_input_reserve = math::checked_as_u128(stable_swap_y(amplification, new_output_reserve, d));
+
+                // Same Newton tolerance as get_input_price: a near-zero
+                // output_amount can converge to new_input_reserve a unit
+                // below input_reserve.
+                new_input_reserve.saturating_sub(input_reserve)
+            }
+        }
+    }
+
+    /// Reserve of `token_account_id` and, paired with it, the reserve of
+    /// the other asset in the pool.
+    fn reserves_for(&self, token_account_id: &AccountId) -> (Balance, Balance) {
+        if token_account_id == &self.token_a {
+            (self.reserve_a, self.reserve_b)
+        } else if token_account_id == &self.token_b {
+            (self.reserve_b, self.reserve_a)
+        } else {
+            env::panic(b"ERR_WRONG_TOKEN")
+        }
+    }
+
+    /// Spot price of `token_a` denominated in `token_b`, scaled by
+    /// `PRICE_SCALE`, the same quantity `place_limit_order` limits are
+    /// expressed against.
+    fn spot_price(&self) -> u128 {
+        math::mul_div(self.reserve_b, PRICE_SCALE, self.reserve_a)
+    }
+}
+
+/// `ft_on_transfer`'s `msg` is parsed as one of these, so a single transfer
+/// carries the pool it belongs to and what the sender wants done with it.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+enum TransferAction {
+    AddLiquidity { token_a: AccountId, token_b: AccountId },
+    Swap { token_a: AccountId, token_b: AccountId, min_output: U128 },
+    PlaceLimitOrder { token_a: AccountId, token_b: AccountId, limit_price: U128 },
+}
+
+#[near_bindgen]
+#[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
+struct Contract {
+    pools: LookupMap<PoolId, Pool>,
+    /// Liquidity legs deposited via `ft_on_transfer` that are still waiting
+    /// on their counterpart before they can be minted into shares, keyed by
+    /// (pool, depositor).
+    pending_deposits: LookupMap<(AccountId, AccountId, AccountId), (Balance, Balance)>,
+    orders: LookupMap<(PoolId, u64), LimitOrder>,
+    user_order_ids: LookupMap<AccountId, Vec<(PoolId, u64)>>,
+    /// Prepaid storage balances, topped up via `storage_deposit`.
+    /// `place_limit_order` debits the storage an order occupies from here
+    /// instead of `env::attached_deposit()`, since it's reached only via
+    /// `ft_on_transfer`, which NEP-141 token contracts call with zero
+    /// attached deposit.
+    storage_balances: LookupMap<AccountId, Balance>,
+}
+
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        assert!(!env::state_exists(), "ERR_CONTRACT_IS_INITIALIZED");
+        Self {
+            pools: LookupMap::new(b"p".to_vec()),
+            pending_deposits: LookupMap::new(b"d".to_vec()),
+            orders: LookupMap::new(b"o".to_vec()),
+            user_order_ids: LookupMap::new(b"u".to_vec()),
+            storage_balances: LookupMap::new(b"b".to_vec()),
+        }
     }
 
-    pub fn get_near_to_token_price(&self, amount: Balance) -> Balance {
-        self.get_output_price(amount, self.near_amount, self.lp_token_amount)
+    /// Tops up the caller's (or `account_id`'s) prepaid storage balance,
+    /// the way `place_limit_order` pays for the orders it rests.
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<ValidAccountId>) {
+        let account_id: AccountId = account_id.map(Into::into).unwrap_or_else(env::predecessor_account_id);
+        let balance = self.storage_balances.get(&account_id).unwrap_or(0);
+        self.storage_balances.insert(&account_id, &(balance + env::attached_deposit()));
     }
 
-    pub fn get_token_to_near_price(&self, amount: Balance) -> Balance {
-        self.get_output_price(amount, self.lp_token_amount, self.near_amount)
+    /// The prepaid storage balance `place_limit_order` will debit from for
+    /// `account_id`.
+    pub fn storage_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.storage_balances.get(account_id.as_ref()).unwrap_or(0))
     }
 
+    /// Registers a new pool for `token_a`/`token_b`, charging the caller's
+    /// attached deposit for the storage the new pool record occupies. Called
+    /// directly (not via `ft_on_transfer`), so unlike
+    /// `place_limit_order` it can charge attached deposit rather than a
+    /// prepaid storage balance.
     #[payable]
-    pub fn swap_near_to_token(&mut self, min_amount: Balance) -> Balance {
-        let payed_amount = env::attached_deposit();
-        let tokens_bought = self.get_input_price(payed_amount, self.near_amount, self.lp_token_amount);
+    pub fn register_pool(&mut self, token_a: ValidAccountId, token_b: ValidAccountId, fee: u32, amplification: Option<u128>) {
+        assert!(fee < FEE_DIVISOR, "ERR_FEE_TOO_LARGE");
+        let pool_id = ordered_pair(token_a.into(), token_b.into());
+        assert!(self.pools.get(&pool_id).is_none(), "ERR_POOL_ALREADY_EXISTS");
+
+        let initial_storage = env::storage_usage();
+        let pool_kind = match amplification {
+            Some(amplification) => {
+                assert!(amplification > 0, "ERR_INVALID_AMPLIFICATION");
+                PoolKind::StableSwap { amplification }
+            }
+            None => PoolKind::ConstantProduct,
+        };
+        let pool = Pool {
+            token_a: pool_id.0.clone(),
+            token_b: pool_id.1.clone(),
+            fee,
+            pool_kind,
+            shares: LookupMap::new(shares_prefix(&pool_id)),
+            shares_total_supply: 0,
+            reserve_a: 0,
+            reserve_b: 0,
+            sell_orders: Vec::new(),
+            buy_orders: Vec::new(),
+            next_order_ordinal: 0,
+        };
+        self.pools.insert(&pool_id, &pool);
+        charge_storage_deposit(initial_storage);
+    }
+
+    pub fn remove_liquidity(&mut self, token_a: ValidAccountId, token_b: ValidAccountId, shares: Balance, min_a: Balance, min_b: Balance) -> Promise {
+        let pool_id = ordered_pair(token_a.into(), token_b.into());
+        let mut pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+        assert!(shares > 0 && pool.shares_total_supply > 0, "ERR_EMPTY_SHARES");
+
+        let amount_a = math::mul_div(shares, pool.reserve_a, pool.shares_total_supply);
+        let amount_b = math::mul_div(shares, pool.reserve_b, pool.shares_total_supply);
+        assert!(amount_a >= min_a && amount_b >= min_b, "ERR_MIN_AMOUNT");
+
+        let account_id = env::predecessor_account_id();
+        let prev_amount = pool.shares.get(&account_id).unwrap_or(0);
+        assert!(prev_amount >= shares, "ERR_NOT_ENOUGH_SHARES");
+
+        if prev_amount == shares {
+            pool.shares.remove(&account_id);
+        } else {
+            pool.shares.insert(&account_id, &(prev_amount - shares));
+        }
+
+        pool.shares_total_supply -= shares;
+        pool.reserve_a -= amount_a;
+        pool.reserve_b -= amount_b;
+        self.pools.insert(&pool_id, &pool);
+
+        let gas_per_leg = (env::prepaid_gas() - GAS_FOR_SWAP - GAS_FOR_RESOLVE_TRANSFER) / 2;
+        ext_fungible_token::ft_transfer(
+            account_id.clone().try_into().unwrap(),
+            U128(amount_a),
+            None,
+            &pool_id.0,
+            NO_DEPOSIT,
+            gas_per_leg
+        )
+        .and(ext_fungible_token::ft_transfer(
+            account_id.clone().try_into().unwrap(),
+            U128(amount_b),
+            None,
+            &pool_id.1,
+            NO_DEPOSIT,
+            gas_per_leg
+        ))
+        .then(ext_self::resolve_remove_liquidity(
+            pool_id.0,
+            pool_id.1,
+            account_id,
+            shares,
+            amount_a,
+            amount_b,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_TRANSFER
+        ))
+    }
+
+    /// Quotes the output of swapping `amount_in` of `token_in` for the
+    /// other asset in the `token_a`/`token_b` pool.
+    pub fn get_return(&self, token_in: ValidAccountId, token_a: ValidAccountId, token_b: ValidAccountId, amount_in: U128) -> U128 {
+        let pool_id = ordered_pair(token_a.into(), token_b.into());
+        let pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+        let (input_reserve, output_reserve) = pool.reserves_for(token_in.as_ref());
 
-        assert!(tokens_bought >= min_amount, "ERR_MIN_TOKENS_BOUGHT");
+        pool.get_input_price(amount_in.into(), input_reserve, output_reserve).into()
+    }
+
+    pub fn shares_balance(&self, token_a: ValidAccountId, token_b: ValidAccountId, account_id: ValidAccountId) -> U128 {
+        let pool_id = ordered_pair(token_a.into(), token_b.into());
+        let pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+        pool.shares.get(account_id.as_ref()).unwrap_or(0).into()
+    }
+
+    /// Cancels a resting limit order, refunding the tokens it was resting
+    /// on back to its owner.
+    pub fn cancel_limit_order(&mut self, token_a: ValidAccountId, token_b: ValidAccountId, ordinal: u64) -> Promise {
+        let pool_id = ordered_pair(token_a.into(), token_b.into());
+        let order_key = (pool_id.clone(), ordinal);
+        let order = self.orders.get(&order_key).expect("ERR_ORDER_NOT_FOUND");
+        assert_eq!(order.account_id, env::predecessor_account_id(), "ERR_NOT_ORDER_OWNER");
 
-        self.near_amount += payed_amount;
-        self.lp_token_amount -= tokens_bought;
+        let mut pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+        if order.token_in == pool.token_a {
+            pool.sell_orders.retain(|entry| entry.ordinal != ordinal);
+        } else {
+            pool.buy_orders.retain(|entry| entry.ordinal != ordinal);
+        }
+        self.pools.insert(&pool_id, &pool);
+        self.orders.remove(&order_key);
+        remove_user_order(&mut self.user_order_ids, &order.account_id, &pool_id, ordinal);
+        self.credit_storage_deposit(&order.account_id, order.storage_deposit);
 
         ext_fungible_token::ft_transfer(
-            env::predecessor_account_id().try_into().unwrap(),
-            U128::from(tokens_bought),
+            order.account_id.try_into().unwrap(),
+            U128(order.amount_in),
             None,
-            &self.token_account_id,
+            &order.token_in,
             NO_DEPOSIT,
             env::prepaid_gas() - GAS_FOR_SWAP
+        )
+    }
+
+    /// Lists `account_id`'s resting limit orders across all pools.
+    pub fn get_open_orders(&self, account_id: ValidAccountId) -> Vec<LimitOrderView> {
+        self.user_order_ids
+            .get(account_id.as_ref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(pool_id, ordinal)| {
+                let order = self.orders.get(&(pool_id.clone(), ordinal)).expect("ERR_ORDER_NOT_FOUND");
+                LimitOrderView {
+                    token_a: pool_id.0,
+                    token_b: pool_id.1,
+                    ordinal: order.ordinal,
+                    token_in: order.token_in,
+                    amount_in: U128(order.amount_in),
+                    limit_price: U128(order.limit_price),
+                }
+            })
+            .collect()
+    }
+
+    /// Rests a new limit order selling `amount_in` of `token_in` once the
+    /// pool's spot price of `token_a` crosses `limit_price`. Reached via
+    /// `ft_on_transfer` once the tokens being sold have actually arrived.
+    fn place_limit_order(&mut self, account_id: &AccountId, token_in: &AccountId, token_a: AccountId, token_b: AccountId, amount_in: Balance, limit_price: u128) {
+        assert!(amount_in > 0, "ERR_EMPTY_AMOUNT");
+        let pool_id = ordered_pair(token_a, token_b);
+        assert!(token_in == &pool_id.0 || token_in == &pool_id.1, "ERR_WRONG_TOKEN");
+
+        let initial_storage = env::storage_usage();
+        let mut pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+        let ordinal = pool.next_order_ordinal;
+        pool.next_order_ordinal += 1;
+
+        if token_in == &pool_id.0 {
+            // Selling token_a: executes once the price rises to the limit,
+            // so the lowest limit_price among sell orders is the most
+            // executable; invert it so the max-heap pops that one first.
+            pool.sell_orders.push(HeapEntry { price_key: u128::MAX - limit_price, ordinal });
+        } else {
+            // Selling token_b (buying token_a): executes once the price
+            // falls to the limit, so the highest limit_price is the most
+            // executable, which the max-heap already pops first.
+            pool.buy_orders.push(HeapEntry { price_key: limit_price, ordinal });
+        }
+        self.pools.insert(&pool_id, &pool);
+
+        // Insert the order (storage_deposit filled in below) before
+        // charging, so the charge covers the storage this insert and the
+        // user_order_ids entry actually consume, not just the pool's heap
+        // entry pushed above.
+        let order_key = (pool_id.clone(), ordinal);
+        let mut order = LimitOrder {
+            ordinal,
+            account_id: account_id.clone(),
+            token_in: token_in.clone(),
+            amount_in,
+            limit_price,
+            storage_deposit: 0,
+        };
+        self.orders.insert(&order_key, &order);
+        add_user_order(&mut self.user_order_ids, account_id, &pool_id, ordinal);
+
+        order.storage_deposit = self.debit_storage_deposit(account_id, initial_storage);
+        self.orders.insert(&order_key, &order);
+    }
+
+    /// Debits `account_id`'s prepaid storage balance (topped up via
+    /// `storage_deposit`) for the storage used since `initial_storage`.
+    /// Unlike `register_pool`'s storage charge, this can't come from
+    /// `env::attached_deposit()`: `place_limit_order` is reached only via
+    /// `ft_on_transfer`, which NEP-141 token contracts call with zero
+    /// attached deposit.
+    fn debit_storage_deposit(&mut self, account_id: &AccountId, initial_storage: u64) -> Balance {
+        let storage_used = env::storage_usage() - initial_storage;
+        let required_deposit = Balance::from(storage_used) * env::storage_byte_cost();
+        let balance = self.storage_balances.get(account_id).unwrap_or(0);
+        assert!(balance >= required_deposit, "ERR_NOT_ENOUGH_STORAGE_DEPOSIT");
+        self.storage_balances.insert(account_id, &(balance - required_deposit));
+        required_deposit
+    }
+
+    /// Credits `amount` back to `account_id`'s prepaid storage balance once
+    /// a resting order that occupied it is cancelled or filled for good.
+    fn credit_storage_deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        let balance = self.storage_balances.get(account_id).unwrap_or(0);
+        self.storage_balances.insert(account_id, &(balance + amount));
+    }
+
+    /// Pops and settles every resting order whose limit is satisfied at
+    /// `pool`'s current spot price, each settlement potentially moving the
+    /// price enough to satisfy the next one. Settlement transfers aren't
+    /// fired here: the caller batches them alongside its own transfer so a
+    /// failure on either side resolves together (see `internal_swap`).
+    fn settle_limit_orders(&mut self, pool_id: &PoolId, pool: &mut Pool) -> Vec<FilledOrder> {
+        let mut filled_orders = Vec::new();
+        loop {
+            let price = pool.spot_price();
+            let filled_sell = self.try_fill_top_order(pool_id, pool, true, price);
+            let filled_buy = self.try_fill_top_order(pool_id, pool, false, price);
+            let settled_any = filled_sell.is_some() || filled_buy.is_some();
+            filled_orders.extend(filled_sell);
+            filled_orders.extend(filled_buy);
+            if !settled_any {
+                break;
+            }
+        }
+        filled_orders
+    }
+
+    fn try_fill_top_order(&mut self, pool_id: &PoolId, pool: &mut Pool, is_sell_side: bool, price: u128) -> Option<FilledOrder> {
+        let entries = if is_sell_side { &mut pool.sell_orders } else { &mut pool.buy_orders };
+        if entries.is_empty() {
+            return None;
+        }
+
+        let mut heap: BinaryHeap<HeapEntry> = entries.drain(..).collect();
+        let top = *heap.peek().unwrap();
+        let satisfied = if is_sell_side {
+            let limit_price = u128::MAX - top.price_key;
+            price >= limit_price
+        } else {
+            price <= top.price_key
+        };
+        if !satisfied {
+            *entries = heap.into_vec();
+            return None;
+        }
+        heap.pop();
+        *entries = heap.into_vec();
+
+        let order_key = (pool_id.clone(), top.ordinal);
+        let order = self.orders.remove(&order_key).expect("ERR_ORDER_NOT_FOUND");
+        remove_user_order(&mut self.user_order_ids, &order.account_id, pool_id, top.ordinal);
+
+        let (token_out, output_amount) = if order.token_in == pool.token_a {
+            let output = pool.get_input_price(order.amount_in, pool.reserve_a, pool.reserve_b);
+            pool.reserve_a += order.amount_in;
+            pool.reserve_b -= output;
+            (pool.token_b.clone(), output)
+        } else {
+            let output = pool.get_input_price(order.amount_in, pool.reserve_b, pool.reserve_a);
+            pool.reserve_b += order.amount_in;
+            pool.reserve_a -= output;
+            (pool.token_a.clone(), output)
+        };
+
+        Some(FilledOrder {
+            ordinal: order.ordinal,
+            account_id: order.account_id,
+            token_in: order.token_in,
+            amount_in: U128(order.amount_in),
+            limit_price: U128(order.limit_price),
+            storage_deposit: U128(order.storage_deposit),
+            is_sell_side,
+            token_out,
+            output_amount: U128(output_amount),
+        })
+    }
+
+    fn internal_deposit_liquidity(&mut self, sender_id: &AccountId, token_in: &AccountId, token_a: AccountId, token_b: AccountId, amount: Balance) {
+        let pool_id = ordered_pair(token_a, token_b);
+        assert!(token_in == &pool_id.0 || token_in == &pool_id.1, "ERR_WRONG_TOKEN");
+        let mut pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+
+        let deposit_key = (pool_id.0.clone(), pool_id.1.clone(), sender_id.clone());
+        let (mut deposited_a, mut deposited_b) = self.pending_deposits.get(&deposit_key).unwrap_or((0, 0));
+        if token_in == &pool_id.0 {
+            deposited_a += amount;
+        } else {
+            deposited_b += amount;
+        }
+
+        if deposited_a == 0 || deposited_b == 0 {
+            self.pending_deposits.insert(&deposit_key, &(deposited_a, deposited_b));
+            return;
+        }
+
+        let (liquidity_minted, used_a, used_b) = if pool.shares_total_supply > 0 {
+            let minted_from_a = math::mul_div(deposited_a, pool.shares_total_supply, pool.reserve_a);
+            let minted_from_b = math::mul_div(deposited_b, pool.shares_total_supply, pool.reserve_b);
+            let liquidity_minted = min(minted_from_a, minted_from_b);
+            let used_a = math::mul_div(liquidity_minted, pool.reserve_a, pool.shares_total_supply);
+            let used_b = math::mul_div(liquidity_minted, pool.reserve_b, pool.shares_total_supply);
+            (liquidity_minted, used_a, used_b)
+        } else {
+            (deposited_a, deposited_a, deposited_b)
+        };
+
+        add_to_collection(&mut pool.shares, sender_id, liquidity_minted);
+        pool.shares_total_supply += liquidity_minted;
+        pool.reserve_a += used_a;
+        pool.reserve_b += used_b;
+        self.pools.insert(&pool_id, &pool);
+
+        deposited_a -= used_a;
+        deposited_b -= used_b;
+        if deposited_a > 0 || deposited_b > 0 {
+            self.pending_deposits.insert(&deposit_key, &(deposited_a, deposited_b));
+        } else {
+            self.pending_deposits.remove(&deposit_key);
+        }
+    }
+
+    fn internal_swap(&mut self, receiver_id: &AccountId, token_in: &AccountId, token_a: AccountId, token_b: AccountId, amount_in: Balance, min_output: Balance) -> Promise {
+        let pool_id = ordered_pair(token_a, token_b);
+        let mut pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+        let (input_reserve, output_reserve) = pool.reserves_for(token_in);
+        let token_out = if token_in == &pool.token_a { pool.token_b.clone() } else { pool.token_a.clone() };
+
+        let output_amount = pool.get_input_price(amount_in, input_reserve, output_reserve);
+        assert!(output_amount >= min_output, "ERR_MIN_OUTPUT");
+
+        if token_in == &pool.token_a {
+            pool.reserve_a += amount_in;
+            pool.reserve_b -= output_amount;
+        } else {
+            pool.reserve_b += amount_in;
+            pool.reserve_a -= output_amount;
+        }
+
+        // Settlement shares the same transfer batch as the swap's own
+        // output leg, so a failed order transfer resolves through the same
+        // callback as a failed swap leg (see resolve_swap). An order whose
+        // own leg succeeds stays settled even if the swap's own leg is
+        // rolled back.
+        let filled_orders = self.settle_limit_orders(&pool_id, &mut pool);
+        self.pools.insert(&pool_id, &pool);
+
+        let num_legs = 1 + filled_orders.len() as u64;
+        // Each settled order adds its own rollback work to resolve_swap, so
+        // its callback gas has to grow with filled_orders, not stay fixed.
+        let gas_for_resolve = GAS_FOR_RESOLVE_TRANSFER + GAS_FOR_RESOLVE_ORDER * filled_orders.len() as u64;
+        let gas_per_leg = (env::prepaid_gas() - GAS_FOR_SWAP - gas_for_resolve) / num_legs;
+
+        let mut transfers = ext_fungible_token::ft_transfer(
+            receiver_id.clone().try_into().unwrap(),
+            U128(output_amount),
+            None,
+            &token_out,
+            NO_DEPOSIT,
+            gas_per_leg
         );
-        tokens_bought
+        for filled in &filled_orders {
+            transfers = transfers.and(ext_fungible_token::ft_transfer(
+                filled.account_id.clone().try_into().unwrap(),
+                filled.output_amount.clone(),
+                None,
+                &filled.token_out,
+                NO_DEPOSIT,
+                gas_per_leg
+            ));
+        }
+
+        transfers.then(ext_self::resolve_swap(
+            pool_id.0,
+            pool_id.1,
+            token_in.clone(),
+            amount_in,
+            output_amount,
+            filled_orders,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas_for_resolve,
+        ))
     }
 
-    pub fn swap_token_to_near(&mut self, sender_id: AccountId, token_amount: Balance, min_near_amount: Balance) -> Promise {
-        let near_bought = self.get_input_price(token_amount, self.lp_token_amount, self.near_amount);
-        assert!(near_bought >= min_near_amount, "ERR_MIN_NEAR_AMOUNT");
+    /// Callback for a swap's outgoing transfer batch: the swap's own output
+    /// leg, followed by one leg per resting order `internal_swap` settled
+    /// alongside it. If the swap's own leg failed, the pool never actually
+    /// gave up `amount_out`, so put the reserves back and refund `amount_in`
+    /// to the sender via the FT standard's resolve convention. For each
+    /// order leg: on success its storage is freed for good, so credit the
+    /// deposit it was resting on back to its owner; on failure, undo the
+    /// settlement and re-rest the order exactly as it was (its storage
+    /// deposit was never released, so no new one is taken).
+    #[private]
+    pub fn resolve_swap(&mut self, token_a: AccountId, token_b: AccountId, token_in: AccountId, amount_in: Balance, amount_out: Balance, filled_orders: Vec<FilledOrder>) -> U128 {
+        assert_eq!(env::promise_results_count(), 1 + filled_orders.len() as u64, "ERR_UNEXPECTED_RESULTS_COUNT");
+        let pool_id = (token_a, token_b);
+        let mut pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+
+        let refund = match env::promise_result(0) {
+            PromiseResult::Successful(_) => 0,
+            _ => {
+                if token_in == pool.token_a {
+                    pool.reserve_a -= amount_in;
+                    pool.reserve_b += amount_out;
+                } else {
+                    pool.reserve_b -= amount_in;
+                    pool.reserve_a += amount_out;
+                }
+                amount_in
+            }
+        };
+
+        for (i, filled) in filled_orders.into_iter().enumerate() {
+            let result_index = (i + 1) as u64;
+            if matches!(env::promise_result(result_index), PromiseResult::Successful(_)) {
+                self.credit_storage_deposit(&filled.account_id, filled.storage_deposit.into());
+                continue;
+            }
 
-        self.near_amount -= near_bought;
-        self.lp_token_amount += token_amount;
+            let order_amount_in: Balance = filled.amount_in.into();
+            let output_amount: Balance = filled.output_amount.into();
+            let limit_price: Balance = filled.limit_price.into();
+            if filled.is_sell_side {
+                pool.reserve_a -= order_amount_in;
+                pool.reserve_b += output_amount;
+                pool.sell_orders.push(HeapEntry { price_key: u128::MAX - limit_price, ordinal: filled.ordinal });
+            } else {
+                pool.reserve_b -= order_amount_in;
+                pool.reserve_a += output_amount;
+                pool.buy_orders.push(HeapEntry { price_key: limit_price, ordinal: filled.ordinal });
+            }
 
-        Promise::new(sender_id.clone()).transfer(near_bought)
+            let order = LimitOrder {
+                ordinal: filled.ordinal,
+                account_id: filled.account_id.clone(),
+                token_in: filled.token_in,
+                amount_in: order_amount_in,
+                limit_price,
+                storage_deposit: filled.storage_deposit.into(),
+            };
+            self.orders.insert(&(pool_id.clone(), filled.ordinal), &order);
+            add_user_order(&mut self.user_order_ids, &filled.account_id, &pool_id, filled.ordinal);
+        }
+
+        self.pools.insert(&pool_id, &pool);
+        U128(refund)
     }
 
-    pub fn shares_balance(&self, account_id: ValidAccountId) -> U128 {
-        self.shares
-            .get(account_id.as_ref())
-            .unwrap_or(0)
-            .into()
+    /// Callback for `remove_liquidity`. If either leg's `ft_transfer`
+    /// failed, re-credit the shares and the reserves that leg represents so
+    /// the caller can retry instead of losing their position.
+    #[private]
+    pub fn resolve_remove_liquidity(&mut self, token_a: AccountId, token_b: AccountId, account_id: AccountId, shares: Balance, amount_a: Balance, amount_b: Balance) {
+        assert_eq!(env::promise_results_count(), 2, "ERR_UNEXPECTED_RESULTS_COUNT");
+        let leg_a_failed = matches!(env::promise_result(0), PromiseResult::Failed);
+        let leg_b_failed = matches!(env::promise_result(1), PromiseResult::Failed);
+        if !leg_a_failed && !leg_b_failed {
+            return;
+        }
+
+        let pool_id = (token_a, token_b);
+        let mut pool = self.pools.get(&pool_id).expect("ERR_POOL_NOT_FOUND");
+        add_to_collection(&mut pool.shares, &account_id, shares);
+        pool.shares_total_supply += shares;
+        if leg_a_failed {
+            pool.reserve_a += amount_a;
+        }
+        if leg_b_failed {
+            pool.reserve_b += amount_b;
+        }
+        self.pools.insert(&pool_id, &pool);
     }
 }
 
@@ -198,23 +793,131 @@ trait FungibleToken {
     fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>);
 }
 
+#[ext_contract(ext_self)]
+trait SelfCallbacks {
+    fn resolve_swap(&mut self, token_a: AccountId, token_b: AccountId, token_in: AccountId, amount_in: Balance, amount_out: Balance, filled_orders: Vec<FilledOrder>) -> U128;
+    fn resolve_remove_liquidity(&mut self, token_a: AccountId, token_b: AccountId, account_id: AccountId, shares: Balance, amount_a: Balance, amount_b: Balance);
+}
+
 trait FungibleTokenReceiver {
-    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> U128;
+    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
 }
 
 impl FungibleTokenReceiver for Contract {
-    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> U128 {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.token_account_id,
-            "ERR_WRONG_TOKEN"
-        );
-        if msg == "liquidity" {
-            self.add_liquidity(sender_id.as_ref(), amount)
-        } else {
-            amount
+    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+        let token_in = env::predecessor_account_id();
+        let action: TransferAction = serde_json::from_str(&msg).expect("ERR_INVALID_MSG");
+        match action {
+            TransferAction::AddLiquidity { token_a, token_b } => {
+                self.internal_deposit_liquidity(sender_id.as_ref(), &token_in, token_a, token_b, amount.into());
+                PromiseOrValue::Value(U128(0))
+            }
+            TransferAction::Swap { token_a, token_b, min_output } => {
+                PromiseOrValue::Promise(self.internal_swap(sender_id.as_ref(), &token_in, token_a, token_b, amount.into(), min_output.into()))
+            }
+            TransferAction::PlaceLimitOrder { token_a, token_b, limit_price } => {
+                self.place_limit_order(sender_id.as_ref(), &token_in, token_a, token_b, amount.into(), limit_price.into());
+                PromiseOrValue::Value(U128(0))
+            }
+        }
+    }
+}
+
+/// Orders a token pair so the same pool is found regardless of which side
+/// the caller names first.
+fn ordered_pair(a: AccountId, b: AccountId) -> PoolId {
+    assert_ne!(a, b, "ERR_SAME_TOKEN");
+    if a < b { (a, b) } else { (b, a) }
+}
+
+/// Storage key prefix for a pool's share book, unique per pool.
+fn shares_prefix(pool_id: &PoolId) -> Vec<u8> {
+    let mut prefix = b"sh:".to_vec();
+    prefix.extend_from_slice(pool_id.0.as_bytes());
+    prefix.push(b':');
+    prefix.extend_from_slice(pool_id.1.as_bytes());
+    prefix
+}
+
+/// Adds `(pool_id, ordinal)` to the account's open-order index.
+fn add_user_order(map: &mut LookupMap<AccountId, Vec<(PoolId, u64)>>, account_id: &AccountId, pool_id: &PoolId, ordinal: u64) {
+    let mut orders = map.get(account_id).unwrap_or_default();
+    orders.push((pool_id.clone(), ordinal));
+    map.insert(account_id, &orders);
+}
+
+/// Removes `(pool_id, ordinal)` from the account's open-order index.
+fn remove_user_order(map: &mut LookupMap<AccountId, Vec<(PoolId, u64)>>, account_id: &AccountId, pool_id: &PoolId, ordinal: u64) {
+    let mut orders = map.get(account_id).unwrap_or_default();
+    orders.retain(|(p, o)| !(p == pool_id && *o == ordinal));
+    if orders.is_empty() {
+        map.remove(account_id);
+    } else {
+        map.insert(account_id, &orders);
+    }
+}
+
+/// Charges the predecessor for the storage used since `initial_storage`,
+/// the way the orderbook contract stakes storage for resting orders, and
+/// refunds any excess attached deposit.
+fn charge_storage_deposit(initial_storage: u64) {
+    let storage_used = env::storage_usage() - initial_storage;
+    let required_deposit = Balance::from(storage_used) * env::storage_byte_cost();
+    let attached = env::attached_deposit();
+    assert!(attached >= required_deposit, "ERR_NOT_ENOUGH_STORAGE_DEPOSIT");
+
+    let refund = attached - required_deposit;
+    if refund > 0 {
+        Promise::new(env::predecessor_account_id()).transfer(refund);
+    }
+}
+
+/// Solves the StableSwap invariant for `D` given the two pool reserves,
+/// via Newton's method. For `n=2`:
+/// `A*n^n*S + D = A*D*n^n + D^(n+1) / (n^n * x * y)`, `Ann = A*n^n = A*4`.
+fn stable_swap_d(amplification: u128, x: Balance, y: Balance) -> U256 {
+    let ann = U256::from(amplification) * U256::from(4u128);
+    let s = U256::from(x) + U256::from(y);
+    if s.is_zero() {
+        return U256::zero();
+    }
+
+    let mut d = s;
+    for _ in 0..255 {
+        let d_p = d * d / (U256::from(2u128) * U256::from(x)) * d / (U256::from(2u128) * U256::from(y));
+        let d_prev = d;
+        d = (ann * s + d_p * U256::from(2u128)) * d
+            / ((ann - U256::from(1u128)) * d + d_p * U256::from(3u128));
+
+        let diff = if d > d_prev { d - d_prev } else { d_prev - d };
+        if diff <= U256::from(1u128) {
+            break;
+        }
+    }
+    d
+}
+
+/// Given the StableSwap invariant `D` and a new balance `x'` for one
+/// reserve, solves the resulting quadratic for the other reserve `y` via
+/// Newton's method: `y = (y*y + c) / (2*y + b - D)`, where for `n=2`,
+/// `c = D^3 / (4 * x' * Ann)`.
+fn stable_swap_y(amplification: u128, new_reserve: Balance, d: U256) -> U256 {
+    let ann = U256::from(amplification) * U256::from(4u128);
+    let x = U256::from(new_reserve);
+    let b = x + d / ann;
+    let c = d * d / (U256::from(2u128) * x) * d / (U256::from(2u128) * ann);
+
+    let mut y = d;
+    for _ in 0..255 {
+        let y_prev = y;
+        y = (y * y + c) / (y * U256::from(2u128) + b - d);
+
+        let diff = if y > y_prev { y - y_prev } else { y_prev - y };
+        if diff <= U256::from(1u128) {
+            break;
         }
     }
+    y
 }
 
 pub fn add_to_collection(
@@ -237,71 +940,261 @@ mod tests {
 
     use super::*;
 
+    fn add_liquidity_msg(token_a: &AccountId, token_b: &AccountId) -> String {
+        format!(
+            "{{\"action\":\"add_liquidity\",\"token_a\":\"{}\",\"token_b\":\"{}\"}}",
+            token_a, token_b
+        )
+    }
+
     #[test]
-    fn test_init_liquidity() {
+    fn test_register_pool_and_add_liquidity() {
         let one_near = 10u128.pow(24);
+        let token_a = accounts(1);
+        let token_b = accounts(2);
+        let lp = accounts(3);
+
         let mut context = VMContextBuilder::new();
-        context.predecessor_account_id(accounts(1));
-        testing_env!(context.build());
-        testing_env!(context.attached_deposit(5 * one_near).build());
-        let mut contract = Contract::new(accounts(1), 3);
-        contract.ft_on_transfer(accounts(1), (10 * one_near).into(), "liquidity".to_owned());
+        context.predecessor_account_id(lp.clone());
+        testing_env!(context.attached_deposit(one_near).build());
+        let mut contract = Contract::new();
+        contract.register_pool(token_a.clone().try_into().unwrap(), token_b.clone().try_into().unwrap(), 3, None);
+
+        let msg = add_liquidity_msg(&token_a, &token_b);
+        testing_env!(context.predecessor_account_id(token_a.clone()).build());
+        contract.ft_on_transfer(lp.clone().try_into().unwrap(), (5 * one_near).into(), msg.clone());
+        testing_env!(context.predecessor_account_id(token_b.clone()).build());
+        contract.ft_on_transfer(lp.clone().try_into().unwrap(), (10 * one_near).into(), msg);
 
-        // Test add_liquidity result
-        let shares_amount: u128 = contract.shares_balance(accounts(1)).into();
-        assert_eq!(shares_amount, 5 * one_near);
+        let shares: u128 = contract.shares_balance(
+            token_a.clone().try_into().unwrap(),
+            token_b.clone().try_into().unwrap(),
+            lp.clone().try_into().unwrap()
+        ).into();
+        assert_eq!(shares, 5 * one_near);
     }
 
-    #[test] 
-    fn test_swap() {
+    #[test]
+    fn test_swap_between_two_tokens() {
         let one_near = 10u128.pow(24);
+        let token_a = accounts(1);
+        let token_b = accounts(2);
+        let lp = accounts(3);
+
         let mut context = VMContextBuilder::new();
-        context.predecessor_account_id(accounts(1));
-        testing_env!(context.build());
-        testing_env!(context.attached_deposit(5 * one_near).build());
-        let mut contract = Contract::new(accounts(1), 3);
-        contract.ft_on_transfer(accounts(1), (10 * one_near).into(), "liquidity".to_owned());
-
-        // Check output price
-        let near_to_token = contract.get_near_to_token_price(one_near);
-        assert_eq!(near_to_token, 557227237267357628440878);
-        let token_to_near = contract.get_token_to_near_price(one_near);        
-        assert_eq!(token_to_near, 2507522567703109327983951);
-
-        // Check input price before swapping 3N for tokens
-        let input_price = contract.get_input_price(3 * one_near, contract.near_amount, contract.lp_token_amount);
-        /* Calculate input price
-        (5 + 3) * (10 - b) = 5 * 10
-        b = 10 - ( 50 / 8 ) = 3.75  */
-        let expected_input_price = one_near / 100 * 375; // similar to * 3.75
-        let expected_input_price_with_fee = 
-            U256::from(expected_input_price) 
-            * U256::from(FEE_DIVISOR - contract.fee) 
-            / U256::from(FEE_DIVISOR);
-        assert_eq!(input_price, expected_input_price_with_fee.as_u128());
-
-        // Swap 3N for tokens, check that pool has 3N more and result tokens less.
-        testing_env!(context.attached_deposit(3 * one_near).build());
-        let result = contract.swap_near_to_token(1);
-        assert_eq!(contract.near_amount, 8 * one_near);
-        assert_eq!(contract.lp_token_amount, 10 * one_near - result);
+        context.predecessor_account_id(lp.clone());
+        testing_env!(context.attached_deposit(one_near).build());
+        let mut contract = Contract::new();
+        contract.register_pool(token_a.clone().try_into().unwrap(), token_b.clone().try_into().unwrap(), 3, None);
+
+        let msg = add_liquidity_msg(&token_a, &token_b);
+        testing_env!(context.predecessor_account_id(token_a.clone()).build());
+        contract.ft_on_transfer(lp.clone().try_into().unwrap(), (5 * one_near).into(), msg.clone());
+        testing_env!(context.predecessor_account_id(token_b.clone()).build());
+        contract.ft_on_transfer(lp.clone().try_into().unwrap(), (10 * one_near).into(), msg);
+
+        let pool_id = ordered_pair(token_a.clone(), token_b.clone());
+        let pool = contract.pools.get(&pool_id).unwrap();
+        let expected_output = pool.get_input_price(3 * one_near, pool.reserve_a, pool.reserve_b);
+
+        let swap_msg = format!(
+            "{{\"action\":\"swap\",\"token_a\":\"{}\",\"token_b\":\"{}\",\"min_output\":\"1\"}}",
+            token_a, token_b
+        );
+        let trader = accounts(4);
+        testing_env!(context.predecessor_account_id(token_a.clone()).build());
+        contract.ft_on_transfer(trader.clone().try_into().unwrap(), (3 * one_near).into(), swap_msg);
+
+        let pool = contract.pools.get(&pool_id).unwrap();
+        assert_eq!(pool.reserve_a, 8 * one_near);
+        assert_eq!(pool.reserve_b, 10 * one_near - expected_output);
     }
 
     #[test]
-    fn test_remove_liquidity() {
+    fn test_stable_swap_near_peg() {
         let one_near = 10u128.pow(24);
+        let token_a = accounts(1);
+        let token_b = accounts(2);
+        let lp = accounts(3);
+
         let mut context = VMContextBuilder::new();
-        context.predecessor_account_id(accounts(1));
-        testing_env!(context.build());
-        testing_env!(context.attached_deposit(5 * one_near).build());
-        let mut contract = Contract::new(accounts(1), 3);
-        contract.ft_on_transfer(accounts(1), (10 * one_near).into(), "liquidity".to_owned());
-        
-        // Withdraw all liquidity, check that nothing left.
-        let shares_amount: u128 = contract.shares_balance(accounts(1)).into();
-        contract.remove_liquidity(shares_amount, 1, 1);
-        assert_eq!(contract.near_amount, 0);
-        assert_eq!(contract.lp_token_amount, 0);
+        context.predecessor_account_id(lp.clone());
+        testing_env!(context.attached_deposit(one_near).build());
+        let mut contract = Contract::new();
+        contract.register_pool(token_a.clone().try_into().unwrap(), token_b.clone().try_into().unwrap(), 3, Some(100));
+
+        let msg = add_liquidity_msg(&token_a, &token_b);
+        testing_env!(context.predecessor_account_id(token_a.clone()).build());
+        contract.ft_on_transfer(lp.clone().try_into().unwrap(), (100 * one_near).into(), msg.clone());
+        testing_env!(context.predecessor_account_id(token_b.clone()).build());
+        contract.ft_on_transfer(lp.clone().try_into().unwrap(), (100 * one_near).into(), msg);
+
+        let pool_id = ordered_pair(token_a, token_b);
+        let pool = contract.pools.get(&pool_id).unwrap();
+
+        // A balanced stable pool should quote close to 1:1, unlike the
+        // constant-product curve which would already show visible slippage.
+        let output = pool.get_input_price(one_near, pool.reserve_a, pool.reserve_b);
+        let slippage = one_near - output;
+        assert!(slippage < one_near / 100, "stable swap slippage too high: {}", slippage);
     }
-}
 
+    #[test]
+    fn test_limit_order_fills_when_swap_crosses_price() {
+        let one_near = 10u128.pow(24);
+        let token_a = accounts(1);
+        let token_b = accounts(2);
+        let lp = accounts(3);
+        let seller = accounts(4);
+        let trader = accounts(5);
+
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(lp.clone());
+        testing_env!(context.attached_deposit(one_near).build());
+        let mut contract = Contract::new();
+        contract.register_pool(token_a.clone().try_into().unwrap(), token_b.clone().try_into().unwrap(), 3, None);
+
+        let msg = add_liquidity_msg(&token_a, &token_b);
+        testing_env!(context.predecessor_account_id(token_a.clone()).build());
+        contract.ft_on_transfer(lp.clone().try_into().unwrap(), (1_000 * one_near).into(), msg.clone());
+        testing_env!(context.predecessor_account_id(token_b.clone()).build());
+        contract.ft_on_transfer(lp.clone().try_into().unwrap(), (1_000 * one_near).into(), msg);
+
+        // place_limit_order is reached only via ft_on_transfer, which a
+        // NEP-141 token contract calls with zero attached deposit, so its
+        // storage has to come out of a prepaid storage_deposit balance, not
+        // env::attached_deposit(). Top that up first, as a real seller
+        // would before ever sending tokens in.
+        testing_env!(context.predecessor_account_id(seller.clone()).attached_deposit(one_near / 100).build());
+        contract.storage_deposit(None);
+
+        // Rest an order selling 10 token_a once its price (in token_b)
+        // rises to at least 1.05, well above the balanced pool's 1.0.
+        let limit_price = PRICE_SCALE + PRICE_SCALE / 20;
+        let place_order_msg = format!(
+            "{{\"action\":\"place_limit_order\",\"token_a\":\"{}\",\"token_b\":\"{}\",\"limit_price\":\"{}\"}}",
+            token_a, token_b, limit_price
+        );
+        testing_env!(context.predecessor_account_id(token_a.clone()).attached_deposit(0).build());
+        contract.ft_on_transfer(seller.clone().try_into().unwrap(), (10 * one_near).into(), place_order_msg);
+
+        let open_orders = contract.get_open_orders(seller.clone().try_into().unwrap());
+        assert_eq!(open_orders.len(), 1);
+
+        // A large token_b -> token_a swap pushes token_a's price well past
+        // the resting order's limit, so it should be settled as a side
+        // effect of the swap.
+        let swap_msg = format!(
+            "{{\"action\":\"swap\",\"token_a\":\"{}\",\"token_b\":\"{}\",\"min_output\":\"1\"}}",
+            token_a, token_b
+        );
+        testing_env!(context.predecessor_account_id(token_b.clone()).attached_deposit(0).build());
+        contract.ft_on_transfer(trader.clone().try_into().unwrap(), (500 * one_near).into(), swap_msg);
+
+        let open_orders = contract.get_open_orders(seller.try_into().unwrap());
+        assert!(open_orders.is_empty(), "limit order should have been settled by the swap");
+    }
+
+    fn empty_pool(token_a: AccountId, token_b: AccountId, pool_kind: PoolKind) -> Pool {
+        Pool {
+            token_a,
+            token_b,
+            fee: 3,
+            pool_kind,
+            shares: LookupMap::new(b"s".to_vec()),
+            shares_total_supply: 0,
+            reserve_a: 0,
+            reserve_b: 0,
+            sell_orders: Vec::new(),
+            buy_orders: Vec::new(),
+            next_order_ordinal: 0,
+        }
+    }
+
+    #[test]
+    fn test_price_math_near_u128_max_reserves() {
+        // Property-style sweep standing in for proptest (this tree ships no
+        // Cargo.toml to pull the crate in with): drive get_input_price and
+        // get_output_price, the actual routed pricing paths, at reserves a
+        // handful of units below u128::MAX, where stable_swap_y's Newton
+        // tolerance is most likely to make a subtraction go negative.
+        let constant_product = empty_pool(accounts(1), accounts(2), PoolKind::ConstantProduct);
+        for &offset in [0u128, 1, 7, 1_000, 1_000_000_000].iter() {
+            let reserve = u128::MAX - offset - 1;
+            let input_amount = 1_000_000u128;
+
+            let output = constant_product.get_input_price(input_amount, reserve, reserve);
+            assert!(output > 0 && output < reserve);
+
+            let input_back = constant_product.get_output_price(output, reserve, reserve - output);
+            assert!(input_back >= input_amount);
+        }
+
+        let stable_swap = empty_pool(accounts(1), accounts(2), PoolKind::StableSwap { amplification: 100 });
+        for &offset in [0u128, 1, 7, 1_000, 1_000_000_000].iter() {
+            let reserve = u128::MAX / 2 - offset;
+            let input_amount = 1_000_000u128;
+
+            let output = stable_swap.get_input_price(input_amount, reserve, reserve);
+            assert!(output <= reserve);
+
+            let output_amount = 1_000u128;
+            let input_needed = stable_swap.get_output_price(output_amount, reserve, reserve);
+            assert!(input_needed > 0);
+        }
+    }
+
+    #[test]
+    fn test_add_remove_liquidity_and_swap_near_u128_max_reserves() {
+        // Same sweep, but through the routed contract handlers
+        // (ft_on_transfer -> internal_deposit_liquidity / internal_swap,
+        // plus remove_liquidity) instead of calling Pool's math directly.
+        let token_a = accounts(1);
+        let token_b = accounts(2);
+        let lp = accounts(3);
+        let trader = accounts(4);
+
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(lp.clone());
+        testing_env!(context.attached_deposit(10u128.pow(24)).build());
+        let mut contract = Contract::new();
+        contract.register_pool(token_a.clone().try_into().unwrap(), token_b.clone().try_into().unwrap(), 3, None);
+
+        // Each iteration withdraws every share at the end, so the next one
+        // starts again from an empty pool.
+        for &offset in [0u128, 1, 7, 1_000, 1_000_000_000].iter() {
+            let near_max = u128::MAX / 4 - offset;
+
+            let msg = add_liquidity_msg(&token_a, &token_b);
+            testing_env!(context.predecessor_account_id(token_a.clone()).build());
+            contract.ft_on_transfer(lp.clone().try_into().unwrap(), U128(near_max), msg.clone());
+            testing_env!(context.predecessor_account_id(token_b.clone()).build());
+            contract.ft_on_transfer(lp.clone().try_into().unwrap(), U128(near_max), msg);
+
+            let pool_id = ordered_pair(token_a.clone(), token_b.clone());
+            let pool = contract.pools.get(&pool_id).unwrap();
+            assert_eq!(pool.reserve_a, near_max);
+            assert_eq!(pool.reserve_b, near_max);
+
+            let swap_amount = near_max / 1_000_000;
+            let swap_msg = format!(
+                "{{\"action\":\"swap\",\"token_a\":\"{}\",\"token_b\":\"{}\",\"min_output\":\"1\"}}",
+                token_a, token_b
+            );
+            testing_env!(context.predecessor_account_id(token_a.clone()).build());
+            contract.ft_on_transfer(trader.clone().try_into().unwrap(), U128(swap_amount), swap_msg);
+
+            let pool = contract.pools.get(&pool_id).unwrap();
+            assert_eq!(pool.reserve_a, near_max + swap_amount);
+            assert!(pool.reserve_b < near_max);
+
+            testing_env!(context.predecessor_account_id(lp.clone()).build());
+            let shares = contract.shares_balance(
+                token_a.clone().try_into().unwrap(),
+                token_b.clone().try_into().unwrap(),
+                lp.clone().try_into().unwrap()
+            ).0;
+            contract.remove_liquidity(token_a.clone().try_into().unwrap(), token_b.clone().try_into().unwrap(), shares, 0, 0);
+        }
+    }
+}
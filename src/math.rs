@@ -0,0 +1,52 @@
+use near_sdk::Balance;
+
+use crate::U256;
+
+/// Converts a `U256` back down to the `u128` reserves/shares are stored as,
+/// panicking instead of silently truncating if the value doesn't fit.
+pub fn checked_as_u128(value: U256) -> Balance {
+    assert!(value <= U256::from(u128::MAX), "ERR_MATH_OVERFLOW");
+    value.as_u128()
+}
+
+/// `a + b`, carried out in `U256` so it can't wrap before the checked
+/// conversion back to `u128` catches it.
+pub fn add(a: Balance, b: Balance) -> Balance {
+    checked_as_u128(U256::from(a) + U256::from(b))
+}
+
+/// `a * b / c`, carried out entirely in `U256`.
+pub fn mul_div(a: Balance, b: Balance, c: Balance) -> Balance {
+    checked_as_u128(U256::from(a) * U256::from(b) / U256::from(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_does_not_wrap() {
+        assert_eq!(add(u128::MAX - 1, 1), u128::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MATH_OVERFLOW")]
+    fn test_add_overflow_panics() {
+        add(u128::MAX, 1);
+    }
+
+    #[test]
+    fn test_mul_div_near_u128_max() {
+        let near_max = u128::MAX - 1_000;
+        // (near_max * near_max) overflows u128 long before the division
+        // brings it back down; mul_div must still return the exact answer.
+        assert_eq!(mul_div(near_max, near_max, near_max), near_max);
+        assert_eq!(mul_div(near_max, 2, 2), near_max);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MATH_OVERFLOW")]
+    fn test_mul_div_overflow_panics() {
+        mul_div(u128::MAX, u128::MAX, 1);
+    }
+}